@@ -7,11 +7,26 @@ use carla::{
 };
 use clap::Parser;
 use itertools::{izip, Itertools};
-use na::coordinates::XYZ;
 use nalgebra as na;
 use pcd_rs::{DataKind, PcdSerialize};
 use rayon::prelude::*;
-use std::{fs, path::PathBuf, thread, thread::spawn, time::Duration};
+use serde::{Deserialize, Serialize};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    fs,
+    fs::{File, OpenOptions},
+    hash::{Hash, Hasher},
+    io::{BufWriter, Write},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    thread::spawn,
+    time::{Duration, Instant},
+};
 
 #[derive(Parser)]
 struct Opts {
@@ -36,6 +51,27 @@ struct Opts {
     #[clap(short = 'j', long, default_value = "0")]
     pub jobs: usize,
 
+    #[clap(long, default_value = "0.0")]
+    pub voxel_size: f64,
+
+    #[clap(long)]
+    pub checkpoint: Option<PathBuf>,
+
+    #[clap(long, default_value = "0.0")]
+    pub min_range: f64,
+
+    #[clap(long, default_value = "inf")]
+    pub max_range: f64,
+
+    #[clap(long, default_value = "0.0")]
+    pub min_intensity: f64,
+
+    #[clap(long)]
+    pub progress: bool,
+
+    #[clap(long)]
+    pub route_order: bool,
+
     pub output_xodr_file: PathBuf,
     pub output_pcd_file: PathBuf,
 }
@@ -71,7 +107,10 @@ fn main() -> Result<()> {
     // Write the .xodr file
     let map = world.map();
     let opendrive_text = map.to_open_drive();
-    fs::write(opts.output_xodr_file, opendrive_text)?;
+
+    // Tie any checkpoint to this map/options combination.
+    let signature = scan_signature(&opendrive_text, &opts);
+    fs::write(&opts.output_xodr_file, &opendrive_text)?;
 
     let waypoints: Vec<_> = map
         .generate_waypoints(opts.sampling_distance)
@@ -79,9 +118,44 @@ fn main() -> Result<()> {
         .collect();
     let n_waypoints = waypoints.len();
 
+    // Resume from a prior run when a matching checkpoint is present.
+    let (done, seed_points, resume) = match &opts.checkpoint {
+        Some(path) => load_checkpoint(path, signature),
+        None => (HashSet::new(), Vec::new(), false),
+    };
+
+    // Open the checkpoint writer once, appending to a matching file or
+    // creating a fresh one with a header.
+    let mut ckpt_writer = match &opts.checkpoint {
+        Some(path) => Some(CheckpointWriter::open(path, signature, resume)?),
+        None => None,
+    };
+
+    // Choose the order in which waypoints are visited. By default this is
+    // generation order; with --route-order the full set is arranged into a
+    // short tour so the capture sweeps the map coherently.
+    let order: Vec<usize> = if opts.route_order {
+        let positions: Vec<(f64, f64)> = waypoints
+            .iter()
+            .map(|wp| {
+                let tf = wp.transform();
+                (tf.translation.x as f64, tf.translation.y as f64)
+            })
+            .collect();
+        plan_route(&positions)
+    } else {
+        (0..n_waypoints).collect()
+    };
+
+    // Only the waypoints not already captured need to be scanned, kept in
+    // visit order.
+    let pending: Vec<usize> = order.into_iter().filter(|i| !done.contains(i)).collect();
+    let n_pending = pending.len();
+
     // Spawn sensors in the simulator
     let (finish_tx, finish_rx) = flume::bounded(n_workers * 2); // used to mark a completion of a job
     let (measure_tx, measure_rx) = flume::bounded(n_workers * 2); // used to collect lidar data
+    let (completed_tx, completed_rx) = flume::unbounded::<(usize, Vec<Point>)>(); // each waypoint's index paired with its points
 
     let sensors: Vec<_> = (0..n_workers)
         .map(|_| -> Result<_> {
@@ -93,14 +167,14 @@ fn main() -> Result<()> {
                 .set_attribute("range", &opts.lidar_range.to_string())?;
             let sensor = builder.spawn_sensor(&na::Isometry3::identity())?;
 
-            let (activate_tx, activate_rx) = flume::bounded::<na::Isometry3<f32>>(1);
+            let (activate_tx, activate_rx) = flume::bounded::<(usize, na::Isometry3<f32>)>(1);
             let finish_tx = finish_tx.clone();
             let measure_tx = measure_tx.clone();
 
             sensor.listen(move |data| {
-                if let Ok(tf) = activate_rx.try_recv() {
+                if let Ok((idx, tf)) = activate_rx.try_recv() {
                     let measure: LidarMeasurement = data.try_into().unwrap();
-                    measure_tx.send((tf, measure)).unwrap();
+                    measure_tx.send((idx, tf, measure)).unwrap();
                     finish_tx.send(()).unwrap();
                 }
             });
@@ -109,49 +183,164 @@ fn main() -> Result<()> {
         })
         .try_collect()?;
 
-    // Start a thread that collects lidar points.
+    // Start a thread that collects lidar points. Each waypoint's points are
+    // shipped over `completed_tx` together with the waypoint index, so the
+    // distribute loop can both accumulate the full cloud and checkpoint a
+    // delta whose points exactly match its confirmed indices.
+    let (min_range, max_range, min_intensity) =
+        (opts.min_range, opts.max_range, opts.min_intensity);
+
+    // Counters sampled by the optional progress monitor.
+    let points_done = Arc::new(AtomicU64::new(0));
+    let waypoints_done = Arc::new(AtomicU64::new(done.len() as u64));
+    let collector_points_done = points_done.clone();
     let collector_handle = spawn(move || {
-        let points: Vec<_> = measure_rx
+        measure_rx
             .into_iter()
-            .take(n_waypoints)
+            .take(n_pending)
             .par_bridge()
-            .flat_map(|(tf, measure)| {
-                let points: Vec<_> = measure
-                    .as_slice()
-                    .iter()
-                    .map(|det| {
+            .for_each(|(idx, tf, measure)| {
+                // Reusable per-thread scratch: the surviving detections'
+                // column-major XYZ and their matching intensities. Clearing
+                // and refilling avoids a fresh allocation per measurement.
+                thread_local! {
+                    static SCRATCH: RefCell<(Vec<f32>, Vec<f32>)> =
+                        const { RefCell::new((Vec::new(), Vec::new())) };
+                }
+
+                let out = SCRATCH.with(|cell| {
+                    let (coords, intensities) = &mut *cell.borrow_mut();
+                    let dets = measure.as_slice();
+                    coords.clear();
+                    intensities.clear();
+                    coords.reserve(dets.len() * 3);
+                    intensities.reserve(dets.len());
+
+                    for det in dets {
                         let LidarDetection {
                             ref point,
                             intensity,
                         } = *det;
-                        let point = tf * point.to_na_point();
-                        let XYZ { x, y, z } = *point;
-                        Point { x, y, z, intensity }
-                    })
-                    .collect();
-                points
-            })
-            .collect();
-        points
+
+                        // Range-gate and drop-off filter using the detection
+                        // in the sensor frame, before the world transform.
+                        let local = point.to_na_point();
+                        let range = local.coords.norm() as f64;
+                        if range < min_range
+                            || range > max_range
+                            || (intensity as f64) < min_intensity
+                        {
+                            continue;
+                        }
+
+                        coords.extend_from_slice(&[local.x, local.y, local.z]);
+                        intensities.push(intensity);
+                    }
+
+                    // Apply the isometry to the whole batch as one rotation
+                    // matrix product plus a column-broadcast translation.
+                    let m = na::Matrix3xX::from_column_slice(coords);
+                    let rotated = tf.rotation.to_rotation_matrix() * m;
+                    let t = tf.translation.vector;
+
+                    let mut out = Vec::with_capacity(intensities.len());
+                    for (col, &intensity) in rotated.column_iter().zip(intensities.iter()) {
+                        out.push(Point {
+                            x: col.x + t.x,
+                            y: col.y + t.y,
+                            z: col.z + t.z,
+                            intensity,
+                        });
+                    }
+                    out
+                });
+
+                collector_points_done.fetch_add(out.len() as u64, Ordering::Relaxed);
+                // Hand the points and their waypoint index off as one unit, so
+                // a flush can never persist a waypoint's points without the
+                // matching index (which would re-scan and double-count it on
+                // resume).
+                completed_tx.send((idx, out)).unwrap();
+            });
     });
 
+    // Spawn the throughput/ETA monitor when --progress is set. An empty map
+    // has nothing to report and would make the percent/ETA math degenerate
+    // (0/0 = NaN), so the monitor is skipped entirely in that case.
+    let progress_handle = if opts.progress && n_waypoints > 0 {
+        let points_done = points_done.clone();
+        let waypoints_done = waypoints_done.clone();
+        let running = Arc::new(AtomicBool::new(true));
+        let thread_running = running.clone();
+        let handle = spawn(move || {
+            let start = Instant::now();
+            let mut last = start;
+            // Work already present at resume must not count towards this
+            // run's average rates, or the ETA is over-optimistic right after
+            // a resume. Baseline the averages on the counts at startup.
+            let base_points = points_done.load(Ordering::Relaxed);
+            let base_waypoints = waypoints_done.load(Ordering::Relaxed);
+            let (mut last_points, mut last_waypoints) = (base_points, base_waypoints);
+
+            while thread_running.load(Ordering::Relaxed) {
+                thread::sleep(Duration::from_secs(1));
+
+                let now = Instant::now();
+                let elapsed = now.duration_since(start).as_secs_f64();
+                let window = now.duration_since(last).as_secs_f64().max(f64::EPSILON);
+                let pts = points_done.load(Ordering::Relaxed);
+                let wps = waypoints_done.load(Ordering::Relaxed);
+
+                let inst_pps = (pts - last_points) as f64 / window;
+                let inst_wps = (wps - last_waypoints) as f64 / window;
+                let avg_pps = (pts - base_points) as f64 / elapsed.max(f64::EPSILON);
+                let avg_wps = (wps - base_waypoints) as f64 / elapsed.max(f64::EPSILON);
+                let percent = wps as f64 / n_waypoints as f64 * 100.0;
+                let remaining = (n_waypoints as u64).saturating_sub(wps);
+                let eta = if avg_wps > 0.0 {
+                    remaining as f64 / avg_wps
+                } else {
+                    f64::INFINITY
+                };
+
+                eprintln!(
+                    "[progress] {wps}/{n_waypoints} waypoints ({percent:.1}%) | \
+                     {inst_pps:.0} pts/s (avg {avg_pps:.0}) | \
+                     {inst_wps:.1} wp/s (avg {avg_wps:.1}) | ETA {eta:.0}s"
+                );
+
+                last = now;
+                last_points = pts;
+                last_waypoints = wps;
+            }
+        });
+        Some((running, handle))
+    } else {
+        None
+    };
+
+    // Master point cloud, seeded with any checkpointed points. Grown on the
+    // main thread as the collector confirms waypoints.
+    let mut points = seed_points;
+
     // Distribute jobs to sensors.
     {
         let mut finish_token_iter = finish_rx.into_iter();
 
-        'waypoint_loop: for chunk in waypoints.chunks(n_workers) {
+        'waypoint_loop: for chunk in pending.chunks(n_workers) {
             // Move each sensor to the desired waypoint.
-            for (wp, (sensor, _)) in izip!(chunk, &sensors) {
-                sensor.set_transform(&wp.transform());
+            for (&idx, (sensor, _)) in izip!(chunk, &sensors) {
+                sensor.set_transform(&waypoints[idx].transform());
             }
 
             // Tick the simulator to ensure the sensor location is
             // updated.
             world.tick();
 
-            // Activate sensors to scan data.
-            for (wp, (_, activate_tx)) in izip!(chunk, &sensors) {
-                let Ok(()) = activate_tx.send(wp.transform()) else {
+            // Activate sensors to scan data, tagging each measurement with
+            // its waypoint index so the collector can confirm it later.
+            for (&idx, (_, activate_tx)) in izip!(chunk, &sensors) {
+                let Ok(()) = activate_tx.send((idx, waypoints[idx].transform())) else {
                     break 'waypoint_loop;
                 };
             }
@@ -159,11 +348,35 @@ fn main() -> Result<()> {
             // Wait for all sensors to finish.
             let count = (&mut finish_token_iter).take(chunk.len()).count();
             assert_eq!(count, chunk.len());
+            waypoints_done.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+
+            // Drain the confirmations that have arrived, appending their
+            // points to the master cloud and checkpointing each index paired
+            // with exactly its own points. A crash after a flush therefore
+            // never persists points without their index, so nothing is
+            // re-scanned and double-counted on resume.
+            drain_confirmed(&completed_rx, &mut points, &mut ckpt_writer)?;
         }
     }
 
     // Wait for all points to be collected.
-    let points = collector_handle.join().unwrap();
+    collector_handle.join().unwrap();
+
+    // Final drain: the collector lags the dispatch loop on `par_bridge`, so
+    // the last chunk's confirmations typically land after the loop exits.
+    // Persist them before writing the `.pcd` so an interruption here resumes
+    // from the tail instead of re-scanning it.
+    drain_confirmed(&completed_rx, &mut points, &mut ckpt_writer)?;
+
+    // Shut the progress monitor down cleanly.
+    if let Some((running, handle)) = progress_handle {
+        running.store(false, Ordering::Relaxed);
+        handle.join().unwrap();
+    }
+
+    // Collapse overlapping returns from adjacent waypoints onto a voxel
+    // grid before writing. Disabled when --voxel-size is 0.
+    let points = voxel_downsample(points, opts.voxel_size);
 
     // Stop sensor listeners.
     for (sensor, _) in sensors {
@@ -201,10 +414,548 @@ fn main() -> Result<()> {
     Ok(())
 }
 
-#[derive(Debug, Clone, PcdSerialize)]
+/// Drain every confirmation currently queued, appending each waypoint's
+/// points to the master cloud and, when a checkpoint is active, writing one
+/// delta frame whose indices and points correspond exactly. Coupling the two
+/// keeps a crash after a flush from leaving points without their index.
+fn drain_confirmed(
+    rx: &flume::Receiver<(usize, Vec<Point>)>,
+    points: &mut Vec<Point>,
+    writer: &mut Option<CheckpointWriter>,
+) -> Result<()> {
+    let mut newly = Vec::new();
+    let mut delta = Vec::new();
+    for (idx, pts) in rx.try_iter() {
+        newly.push(idx);
+        points.extend_from_slice(&pts);
+        delta.extend(pts);
+    }
+    if let Some(writer) = writer {
+        if !newly.is_empty() {
+            writer.write_delta(&newly, &delta)?;
+        }
+    }
+    Ok(())
+}
+
+/// Order waypoints into a single short tour over their 2D positions:
+/// nearest-neighbor construction from waypoint 0 followed by 2-opt
+/// refinement. The returned vector is a permutation of `0..positions.len()`.
+fn plan_route(positions: &[(f64, f64)]) -> Vec<usize> {
+    let n = positions.len();
+    if n <= 2 {
+        return (0..n).collect();
+    }
+
+    // Greedy nearest-neighbor tour, using a spatial grid so each lookup
+    // stays cheap even on large maps.
+    let mut grid = SpatialGrid::new(positions);
+    let mut tour = Vec::with_capacity(n);
+    let mut current = 0;
+    grid.remove(0);
+    tour.push(0);
+    for _ in 1..n {
+        let Some(next) = grid.nearest(positions[current]) else {
+            break;
+        };
+        grid.remove(next);
+        tour.push(next);
+        current = next;
+    }
+
+    two_opt(&mut tour, positions);
+    tour
+}
+
+/// Squared Euclidean distance between two 2D points.
+fn dist2((ax, ay): (f64, f64), (bx, by): (f64, f64)) -> f64 {
+    let (dx, dy) = (ax - bx, ay - by);
+    dx * dx + dy * dy
+}
+
+/// In-place 2-opt improvement of an open tour: repeatedly reverse the
+/// segment between edges `(i, i+1)` and `(j, j+1)` whenever doing so
+/// shortens the path, stopping when a full pass finds no improvement or the
+/// iteration cap is reached.
+fn two_opt(tour: &mut [usize], positions: &[(f64, f64)]) {
+    let n = tour.len();
+    if n < 4 {
+        return;
+    }
+
+    const MAX_PASSES: usize = 1000;
+    let dist = |a: usize, b: usize| dist2(positions[a], positions[b]).sqrt();
+
+    let mut improved = true;
+    let mut passes = 0;
+    while improved && passes < MAX_PASSES {
+        improved = false;
+        passes += 1;
+        for i in 0..n - 1 {
+            for j in i + 1..n - 1 {
+                let before = dist(tour[i], tour[i + 1]) + dist(tour[j], tour[j + 1]);
+                let after = dist(tour[i], tour[j]) + dist(tour[i + 1], tour[j + 1]);
+                if after + 1e-9 < before {
+                    tour[i + 1..=j].reverse();
+                    improved = true;
+                }
+            }
+        }
+    }
+}
+
+/// A uniform grid over 2D positions supporting removal and
+/// nearest-remaining-point queries, used to keep nearest-neighbor tour
+/// construction near-linear instead of quadratic.
+struct SpatialGrid {
+    cell: f64,
+    min_x: f64,
+    min_y: f64,
+    cells: HashMap<(i64, i64), Vec<usize>>,
+    positions: Vec<(f64, f64)>,
+}
+
+impl SpatialGrid {
+    fn new(positions: &[(f64, f64)]) -> Self {
+        let (mut min_x, mut min_y) = (f64::INFINITY, f64::INFINITY);
+        let (mut max_x, mut max_y) = (f64::NEG_INFINITY, f64::NEG_INFINITY);
+        for &(x, y) in positions {
+            min_x = min_x.min(x);
+            min_y = min_y.min(y);
+            max_x = max_x.max(x);
+            max_y = max_y.max(y);
+        }
+
+        // Aim for roughly one point per cell on average.
+        let span = (max_x - min_x).max(max_y - min_y).max(1.0);
+        let cell = (span / (positions.len() as f64).sqrt()).max(1e-3);
+
+        let mut grid = SpatialGrid {
+            cell,
+            min_x,
+            min_y,
+            cells: HashMap::new(),
+            positions: positions.to_vec(),
+        };
+        for (i, &pos) in positions.iter().enumerate() {
+            grid.cells.entry(grid.key(pos)).or_default().push(i);
+        }
+        grid
+    }
+
+    fn key(&self, (x, y): (f64, f64)) -> (i64, i64) {
+        (
+            ((x - self.min_x) / self.cell).floor() as i64,
+            ((y - self.min_y) / self.cell).floor() as i64,
+        )
+    }
+
+    fn remove(&mut self, idx: usize) {
+        let key = self.key(self.positions[idx]);
+        if let Some(bucket) = self.cells.get_mut(&key) {
+            bucket.retain(|&i| i != idx);
+            if bucket.is_empty() {
+                self.cells.remove(&key);
+            }
+        }
+    }
+
+    /// Scan the ring of cells at Chebyshev distance `radius` from `center`,
+    /// updating `best` with the nearest contained point to `from`.
+    fn scan_ring(
+        &self,
+        from: (f64, f64),
+        center: (i64, i64),
+        radius: i64,
+        best: &mut Option<(f64, usize)>,
+    ) {
+        let (cx, cy) = center;
+        for gx in cx - radius..=cx + radius {
+            for gy in cy - radius..=cy + radius {
+                // Only the boundary of the square ring is new at this radius.
+                if (gx - cx).abs() != radius && (gy - cy).abs() != radius {
+                    continue;
+                }
+                if let Some(bucket) = self.cells.get(&(gx, gy)) {
+                    for &i in bucket {
+                        let d = dist2(from, self.positions[i]);
+                        if best.is_none_or(|(bd, _)| d < bd) {
+                            *best = Some((d, i));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn nearest(&self, from: (f64, f64)) -> Option<usize> {
+        if self.cells.is_empty() {
+            return None;
+        }
+
+        let center = self.key(from);
+        let mut best: Option<(f64, usize)> = None;
+        let mut radius = 0;
+        loop {
+            self.scan_ring(from, center, radius, &mut best);
+
+            // Any point in an unscanned ring `radius + 1` sits at least
+            // `radius * cell` away from `from`, so once the current best is
+            // closer than that bound no farther ring can beat it.
+            if let Some((best_d2, _)) = best {
+                let bound = radius as f64 * self.cell;
+                if best_d2 <= bound * bound {
+                    break;
+                }
+            }
+
+            radius += 1;
+        }
+
+        best.map(|(_, i)| i)
+    }
+}
+
+/// Merge points onto a voxel grid of edge `voxel_size` meters, replacing
+/// each occupied voxel with the centroid of the points that fell in it.
+///
+/// The accumulation is partitioned across rayon threads into partial
+/// `HashMap`s keyed by `(floor(x/s), floor(y/s), floor(z/s))` and merged,
+/// so adjacent-waypoint duplicates collapse without a global lock. A
+/// `voxel_size` of 0 (or negative) returns the points untouched.
+fn voxel_downsample(points: Vec<Point>, voxel_size: f64) -> Vec<Point> {
+    if voxel_size <= 0.0 {
+        return points;
+    }
+    let s = voxel_size;
+
+    let voxels = points
+        .par_iter()
+        .fold(
+            HashMap::new,
+            |mut map: HashMap<(i64, i64, i64), (f64, f64, f64, f64, u32)>, point| {
+                let key = (
+                    (point.x as f64 / s).floor() as i64,
+                    (point.y as f64 / s).floor() as i64,
+                    (point.z as f64 / s).floor() as i64,
+                );
+                let bucket = map.entry(key).or_insert((0.0, 0.0, 0.0, 0.0, 0));
+                bucket.0 += point.x as f64;
+                bucket.1 += point.y as f64;
+                bucket.2 += point.z as f64;
+                bucket.3 += point.intensity as f64;
+                bucket.4 += 1;
+                map
+            },
+        )
+        .reduce(HashMap::new, |mut acc, partial| {
+            for (key, (sx, sy, sz, si, count)) in partial {
+                let bucket = acc.entry(key).or_insert((0.0, 0.0, 0.0, 0.0, 0));
+                bucket.0 += sx;
+                bucket.1 += sy;
+                bucket.2 += sz;
+                bucket.3 += si;
+                bucket.4 += count;
+            }
+            acc
+        });
+
+    voxels
+        .into_values()
+        .map(|(sx, sy, sz, si, count)| {
+            let count = count as f64;
+            Point {
+                x: (sx / count) as f32,
+                y: (sy / count) as f32,
+                z: (sz / count) as f32,
+                intensity: (si / count) as f32,
+            }
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, PcdSerialize, Serialize, Deserialize)]
 struct Point {
     pub x: f32,
     pub y: f32,
     pub z: f32,
     pub intensity: f32,
 }
+
+/// Framed, append-only on-disk format for an interrupted scan.
+///
+/// The file begins with a [`CheckpointHeader`] frame carrying the scan
+/// `signature`, followed by any number of [`CheckpointDelta`] frames, each
+/// holding the waypoint indices and points gathered since the previous
+/// flush. Appending deltas keeps every flush O(delta) rather than rewriting
+/// the whole growing cloud. Each frame is a little-endian `u32` byte length
+/// followed by that many MessagePack bytes.
+#[derive(Serialize, Deserialize)]
+struct CheckpointHeader {
+    signature: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CheckpointDelta {
+    completed: Vec<usize>,
+    points: Vec<Point>,
+}
+
+/// Read one length-prefixed frame starting at `pos`, advancing it past the
+/// frame. Returns `None` at a clean end-of-file or on a truncated frame
+/// (e.g. a flush interrupted mid-write).
+fn read_frame(bytes: &[u8], pos: &mut usize) -> Option<Vec<u8>> {
+    let header_end = pos.checked_add(4)?;
+    if header_end > bytes.len() {
+        return None;
+    }
+    let len = u32::from_le_bytes(bytes[*pos..header_end].try_into().unwrap()) as usize;
+    let frame_end = header_end.checked_add(len)?;
+    if frame_end > bytes.len() {
+        return None;
+    }
+    let frame = bytes[header_end..frame_end].to_vec();
+    *pos = frame_end;
+    Some(frame)
+}
+
+/// Append one length-prefixed frame.
+fn write_frame(writer: &mut impl Write, bytes: &[u8]) -> Result<()> {
+    writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+    writer.write_all(bytes)?;
+    Ok(())
+}
+
+/// Append-only writer for a checkpoint's delta frames.
+struct CheckpointWriter {
+    file: BufWriter<File>,
+}
+
+impl CheckpointWriter {
+    /// Open the checkpoint for writing. When `resume` is true the existing
+    /// file is extended; otherwise it is (re)created with a fresh header.
+    fn open(path: &PathBuf, signature: u64, resume: bool) -> Result<Self> {
+        let file = if resume {
+            BufWriter::new(OpenOptions::new().append(true).open(path)?)
+        } else {
+            let mut file = BufWriter::new(File::create(path)?);
+            write_frame(&mut file, &rmp_serde::to_vec(&CheckpointHeader { signature })?)?;
+            file.flush()?;
+            file
+        };
+        Ok(Self { file })
+    }
+
+    /// Append one delta frame for the points and waypoint indices gathered
+    /// since the previous flush, then flush it out to the OS. This is a
+    /// `BufWriter` flush into the page cache, not an fsync: it survives a
+    /// process kill but not a power loss.
+    fn write_delta(&mut self, completed: &[usize], points: &[Point]) -> Result<()> {
+        let delta = CheckpointDelta {
+            completed: completed.to_vec(),
+            points: points.to_vec(),
+        };
+        write_frame(&mut self.file, &rmp_serde::to_vec(&delta)?)?;
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+/// Derive a stable signature for the current map and the scan options that
+/// affect which points get produced, so a checkpoint is only reused when it
+/// was taken against a matching configuration.
+fn scan_signature(opendrive: &str, opts: &Opts) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    opendrive.hash(&mut hasher);
+    opts.sampling_distance.to_bits().hash(&mut hasher);
+    opts.lidar_range.to_bits().hash(&mut hasher);
+    opts.rotation_frequency.to_bits().hash(&mut hasher);
+    opts.points_per_second.hash(&mut hasher);
+    opts.lidar_channels.hash(&mut hasher);
+    opts.min_range.to_bits().hash(&mut hasher);
+    opts.max_range.to_bits().hash(&mut hasher);
+    opts.min_intensity.to_bits().hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Replay a checkpoint's delta frames if the file exists and its header
+/// matches `signature`. Returns the set of completed waypoint indices, the
+/// points gathered so far, and whether the file is a resumable match (so the
+/// caller appends to it rather than starting a fresh one). A missing,
+/// unreadable, or mismatching file is treated as no checkpoint.
+fn load_checkpoint(path: &PathBuf, signature: u64) -> (HashSet<usize>, Vec<Point>, bool) {
+    let fresh = || (HashSet::new(), Vec::new(), false);
+
+    let Ok(bytes) = fs::read(path) else {
+        return fresh();
+    };
+    let mut pos = 0;
+    let Some(header) = read_frame(&bytes, &mut pos) else {
+        return fresh();
+    };
+    match rmp_serde::from_slice::<CheckpointHeader>(&header) {
+        Ok(header) if header.signature == signature => {}
+        _ => return fresh(),
+    }
+
+    let mut completed = HashSet::new();
+    let mut points = Vec::new();
+    while let Some(frame) = read_frame(&bytes, &mut pos) {
+        let Ok(delta) = rmp_serde::from_slice::<CheckpointDelta>(&frame) else {
+            break;
+        };
+        completed.extend(delta.completed);
+        points.extend(delta.points);
+    }
+    (completed, points, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(x: f32, y: f32, z: f32, intensity: f32) -> Point {
+        Point {
+            x,
+            y,
+            z,
+            intensity,
+        }
+    }
+
+    #[test]
+    fn voxel_downsample_disabled_is_identity() {
+        let points = vec![point(0.0, 0.0, 0.0, 1.0), point(10.0, 10.0, 10.0, 2.0)];
+        let out = voxel_downsample(points.clone(), 0.0);
+        assert_eq!(out.len(), points.len());
+    }
+
+    #[test]
+    fn voxel_downsample_collapses_same_voxel_to_centroid() {
+        // Two points inside the same 1 m voxel collapse to their centroid,
+        // intensity included.
+        let out = voxel_downsample(
+            vec![point(0.1, 0.2, 0.3, 2.0), point(0.3, 0.4, 0.5, 4.0)],
+            1.0,
+        );
+        assert_eq!(out.len(), 1);
+        assert!((out[0].x - 0.2).abs() < 1e-5);
+        assert!((out[0].y - 0.3).abs() < 1e-5);
+        assert!((out[0].z - 0.4).abs() < 1e-5);
+        assert!((out[0].intensity - 3.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn voxel_downsample_keeps_distinct_voxels() {
+        let out = voxel_downsample(
+            vec![point(0.1, 0.1, 0.1, 1.0), point(5.1, 5.1, 5.1, 1.0)],
+            1.0,
+        );
+        assert_eq!(out.len(), 2);
+    }
+
+    fn is_permutation(tour: &[usize], n: usize) -> bool {
+        let mut seen = tour.to_vec();
+        seen.sort_unstable();
+        seen == (0..n).collect::<Vec<_>>()
+    }
+
+    fn path_len(tour: &[usize], pos: &[(f64, f64)]) -> f64 {
+        tour.windows(2)
+            .map(|w| dist2(pos[w[0]], pos[w[1]]).sqrt())
+            .sum()
+    }
+
+    #[test]
+    fn plan_route_returns_permutation() {
+        assert!(plan_route(&[]).is_empty());
+        assert_eq!(plan_route(&[(0.0, 0.0)]), vec![0]);
+        assert!(is_permutation(&plan_route(&[(0.0, 0.0), (1.0, 0.0)]), 2));
+
+        let grid: Vec<(f64, f64)> = (0..5).flat_map(|x| (0..5).map(move |y| (x as f64, y as f64))).collect();
+        let tour = plan_route(&grid);
+        assert!(is_permutation(&tour, grid.len()));
+        assert_eq!(tour[0], 0);
+    }
+
+    #[test]
+    fn two_opt_never_lengthens() {
+        // A deliberately crossed route that 2-opt should be able to shorten.
+        let pos = vec![(0.0, 0.0), (1.0, 1.0), (1.0, 0.0), (0.0, 1.0)];
+        let mut tour: Vec<usize> = (0..pos.len()).collect();
+        let before = path_len(&tour, &pos);
+        two_opt(&mut tour, &pos);
+        assert!(is_permutation(&tour, pos.len()));
+        assert!(path_len(&tour, &pos) <= before + 1e-9);
+    }
+
+    #[test]
+    fn spatial_grid_nearest_matches_brute_force() {
+        let pos: Vec<(f64, f64)> = (0..7)
+            .flat_map(|x| (0..7).map(move |y| (x as f64 * 1.3, y as f64 * 0.7)))
+            .collect();
+        let grid = SpatialGrid::new(&pos);
+
+        for &query in &[(2.1, 3.4), (-5.0, -5.0), (100.0, 0.0), (4.0, 2.0)] {
+            let got = grid.nearest(query).unwrap();
+            let brute = (0..pos.len())
+                .min_by(|&a, &b| {
+                    dist2(query, pos[a])
+                        .partial_cmp(&dist2(query, pos[b]))
+                        .unwrap()
+                })
+                .unwrap();
+            // Compare by distance so ties are acceptable.
+            assert!((dist2(query, pos[got]) - dist2(query, pos[brute])).abs() < 1e-9);
+        }
+    }
+
+    fn temp_path(tag: &str) -> PathBuf {
+        let mut path = std::env::temp_dir();
+        path.push(format!(
+            "carla_map_saver_test_{}_{tag}.ckpt",
+            std::process::id()
+        ));
+        path
+    }
+
+    #[test]
+    fn checkpoint_round_trips() {
+        let sig = 0xABCD_1234u64;
+        let path = temp_path("roundtrip");
+        let _ = fs::remove_file(&path);
+
+        {
+            let mut writer = CheckpointWriter::open(&path, sig, false).unwrap();
+            writer.write_delta(&[0, 1], &[point(1.0, 2.0, 3.0, 4.0)]).unwrap();
+            writer
+                .write_delta(&[2], &[point(5.0, 6.0, 7.0, 8.0), point(9.0, 0.0, 1.0, 2.0)])
+                .unwrap();
+        }
+
+        let (completed, points, resume) = load_checkpoint(&path, sig);
+        assert!(resume);
+        let mut got: Vec<usize> = completed.into_iter().collect();
+        got.sort_unstable();
+        assert_eq!(got, vec![0, 1, 2]);
+        assert_eq!(points.len(), 3);
+        assert!((points[0].x - 1.0).abs() < 1e-6);
+
+        // A signature mismatch is treated as no checkpoint.
+        let (c2, p2, resume2) = load_checkpoint(&path, sig ^ 0xFF);
+        assert!(!resume2);
+        assert!(c2.is_empty() && p2.is_empty());
+
+        // A truncated final frame is dropped cleanly; earlier frames survive.
+        let mut bytes = fs::read(&path).unwrap();
+        bytes.truncate(bytes.len() - 3);
+        let truncated = temp_path("truncated");
+        fs::write(&truncated, &bytes).unwrap();
+        let (c3, _, resume3) = load_checkpoint(&truncated, sig);
+        assert!(resume3);
+        assert_eq!(c3, HashSet::from([0usize, 1]));
+
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(&truncated);
+    }
+}